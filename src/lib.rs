@@ -1,134 +1,409 @@
-use i2cdev::core::*;
-use i2cdev::linux::{LinuxI2CDevice, LinuxI2CError};
+#![cfg_attr(not(any(feature = "linux", test)), no_std)]
 
-const DEVICE_ADDR: u16 = 0x29;
+use embedded_hal::i2c::I2c;
+
+#[cfg(feature = "linux")]
+const DEVICE_ADDR: u8 = 0x29;
 const COMMAND_BIT: u8 = 0xA0;
 const REGISTER_DEVICE_ID: u8 = 0x12;
 const REGISTER_ENABLE: u8 = 0x00;
 const REGISTER_CONTROL: u8 = 0x01;
+const REGISTER_AILTL: u8 = 0x04;
+const REGISTER_AIHTL: u8 = 0x06;
+const REGISTER_NPAILTL: u8 = 0x08;
+const REGISTER_NPAIHTL: u8 = 0x0A;
+const REGISTER_PERSIST: u8 = 0x0C;
+const REGISTER_STATUS: u8 = 0x13;
 const REGISTER_CHAN0_LOW: u8 = 0x14;
 const REGISTER_CHAN1_LOW: u8 = 0x16;
 
+const STATUS_AVALID: u8 = 0x01;
+
+// Upper bound on STATUS polls in the blocking `read()` before giving up; one
+// integration cycle is always long enough to complete within this many reads.
+const AVALID_POLL_LIMIT: u32 = 100_000;
+
+// Special-function command that clears the ALS interrupt (both INT and NPINT).
+const CLEAR_INTERRUPT: u8 = COMMAND_BIT | 0x60 | 0x06;
+
 const POWER_OFF: u8 = 0x00;
 const POWER_ON: u8 = 0x01;
 const ENABLE_AEN: u8 = 0x02;
 const ENABLE_AIEN: u8 = 0x10;
 const ENABLE_NPIEN: u8 = 0x80;
 
+const DEVICE_ID: u8 = 0x50;
+
 const LUX_DF: f32 = 408.0;
 
-pub struct Tsl2591 {
+// Auto-ranging bounds. ch0 is considered saturated at or above `saturation()`
+// for the active integration time, and too dark below `AUTO_LOW_THRESHOLD`.
+const AUTO_SATURATION_100MS: u32 = 36863;
+const AUTO_LOW_THRESHOLD: u16 = 100;
+const AUTO_MAX_STEPS: u8 = 8;
+
+/// Count at which ch0 is treated as saturated for the given integration time.
+///
+/// This sits at 90% of the full-scale count (which scales with integration
+/// time up to the 16-bit ADC limit) and is always strictly below the `0xFFFF`
+/// overflow sentinel, so the auto-ranging step-down path can trigger before a
+/// reading is rejected as [`Error::Overflow`].
+fn saturation(integration: IntegrationTime) -> u16 {
+    let scale = integration as u32 + 1;
+    let full_scale = (AUTO_SATURATION_100MS * scale).min(0xFFFE);
+    (full_scale * 9 / 10) as u16
+}
+
+/// Errors that can occur while talking to the sensor.
+#[derive(Debug)]
+pub enum Error<E> {
+    /// The underlying I2C bus returned an error.
+    I2c(E),
+    /// A channel saturated (`0xFFFF`) so the reading cannot be trusted.
+    Overflow,
+    /// The device id register did not contain the expected `0x50`.
+    WrongDeviceId(u8),
+    /// `AVALID` never asserted within the poll budget, so no completed
+    /// conversion was available to read.
+    Timeout,
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(error: E) -> Self {
+        Error::I2c(error)
+    }
+}
+
+pub struct Tsl2591<I> {
     enabled: bool,
     integration: IntegrationTime,
     gain: Gain,
-    i2cdev: LinuxI2CDevice
+    algorithm: LuxAlgorithm,
+    address: u8,
+    i2c: I,
 }
 
-impl Tsl2591 {
-    pub fn new() -> Self {
-        let mut dev = LinuxI2CDevice::new("/dev/i2c-1", DEVICE_ADDR).unwrap();
-
-        let device_id = dev.smbus_read_byte_data(COMMAND_BIT | REGISTER_DEVICE_ID);
-        
-        match device_id {
-            Ok(id) => println!("Found tsl2591: {}", id),
-            Err(error) => panic!("Failed to find tsl2591: {:?}", error)
-        };
-
+impl<I, E> Tsl2591<I>
+where
+    I: I2c<Error = E>,
+{
+    pub fn new(i2c: I, address: u8) -> Result<Self, Error<E>> {
         let mut tsl_2591 = Tsl2591 {
             enabled: false,
             integration: IntegrationTime::IT100MS,
             gain: Gain::MEDIUM,
-            i2cdev: dev
+            algorithm: LuxAlgorithm::Formula,
+            address,
+            i2c,
         };
 
+        let id = tsl_2591.read_byte(REGISTER_DEVICE_ID)?;
+        if id != DEVICE_ID {
+            return Err(Error::WrongDeviceId(id));
+        }
+
         // low power mode by default
-        tsl_2591.disable();
+        tsl_2591.disable()?;
+
+        Ok(tsl_2591)
+    }
+
+    /// Blocking read: polls the `STATUS` register until the `AVALID` bit is set
+    /// so the returned lux always comes from a completed integration cycle.
+    ///
+    /// Returns [`Error::Timeout`] if `AVALID` never asserts within the poll
+    /// budget rather than latching stale channel data.
+    pub fn read(&mut self) -> Result<f32, Error<E>> {
+        for _ in 0..AVALID_POLL_LIMIT {
+            if self.is_valid()? {
+                let (ch0, ch1) = self.get_full_luminosity()?;
+                return self.calculate_lux(ch0, ch1);
+            }
+        }
+
+        Err(Error::Timeout)
+    }
 
-        tsl_2591
+    /// Non-blocking read: returns `Ok(None)` if a fresh conversion isn't ready
+    /// yet (`AVALID` clear), so it composes with async/event loops.
+    pub fn try_read(&mut self) -> Result<Option<f32>, Error<E>> {
+        if !self.is_valid()? {
+            return Ok(None);
+        }
+
+        let (ch0, ch1) = self.get_full_luminosity()?;
+        self.calculate_lux(ch0, ch1).map(Some)
+    }
+
+    /// Read the raw `(ch0, ch1)` channel counts without applying any lux
+    /// conversion, for callers doing their own calibration or logging.
+    pub fn read_channels(&mut self) -> Result<(u16, u16), Error<E>> {
+        self.read_raw()
+    }
+
+    /// Full-spectrum (visible + infrared) count, i.e. ch0.
+    pub fn full_spectrum(&mut self) -> Result<u16, Error<E>> {
+        let (ch0, _) = self.read_raw()?;
+        Ok(ch0)
+    }
+
+    /// Infrared-only count, i.e. ch1.
+    pub fn infrared(&mut self) -> Result<u16, Error<E>> {
+        let (_, ch1) = self.read_raw()?;
+        Ok(ch1)
+    }
+
+    /// Visible-light count, i.e. `ch0 - ch1` saturating at 0.
+    pub fn visible(&mut self) -> Result<u16, Error<E>> {
+        let (ch0, ch1) = self.read_raw()?;
+        Ok(ch0.saturating_sub(ch1))
+    }
+
+    /// Whether the `AVALID` bit in the `STATUS` register is set, i.e. a new ALS
+    /// integration cycle has completed since the channels were last read.
+    pub fn is_valid(&mut self) -> Result<bool, Error<E>> {
+        let status = self.read_byte(REGISTER_STATUS)?;
+        Ok(status & STATUS_AVALID != 0)
     }
 
-    pub fn read(&mut self) -> f32 {
-        let (ch0, ch1) = self.get_full_luminosity();
-        self.calculate_lux(ch0, ch1)
+    /// Take a reading, adjusting gain and integration time to keep ch0 inside a
+    /// sensible window, and report the lux computed at the settled operating
+    /// point alongside the `(IntegrationTime, Gain)` that was used.
+    ///
+    /// If ch0 saturates for the current integration time the sensitivity is
+    /// stepped down; if it falls below [`AUTO_LOW_THRESHOLD`] it is stepped up.
+    /// The loop is bounded so a pathological bus can never hang the caller.
+    pub fn read_auto(&mut self) -> Result<(f32, IntegrationTime, Gain), Error<E>> {
+        for _ in 0..AUTO_MAX_STEPS {
+            // Wait for a completed conversion, then read the raw channels: a
+            // fully saturated `0xFFFF` is a signal to range down here, not an
+            // error to propagate. Gating on `AVALID` ensures we never range off
+            // a stale (pre-reconfiguration) or incomplete conversion.
+            let (ch0, ch1) = self.read_raw_blocking()?;
+
+            if ch0 >= saturation(self.integration) {
+                // Too bright: drop gain first, then integration time.
+                if let Some(gain) = self.gain.lower() {
+                    self.set_timing_gain(self.integration, gain)?;
+                    continue;
+                } else if let Some(integration) = self.integration.shorter() {
+                    self.set_timing_gain(integration, self.gain)?;
+                    continue;
+                }
+                // Already at the least sensitive setting; report the overflow.
+                return Err(Error::Overflow);
+            } else if ch0 < AUTO_LOW_THRESHOLD {
+                // Too dark: raise integration time first, then gain.
+                if let Some(integration) = self.integration.longer() {
+                    self.set_timing_gain(integration, self.gain)?;
+                    continue;
+                } else if let Some(gain) = self.gain.higher() {
+                    self.set_timing_gain(self.integration, gain)?;
+                    continue;
+                }
+                // Already at the most sensitive setting; use what we have.
+            }
+
+            let lux = self.calculate_lux(ch0, ch1)?;
+            return Ok((lux, self.integration, self.gain));
+        }
+
+        // Couldn't settle within the iteration bound; take one more completed
+        // conversion at the final setting and report that.
+        let (ch0, ch1) = self.read_raw_blocking()?;
+        let lux = self.calculate_lux(ch0, ch1)?;
+        Ok((lux, self.integration, self.gain))
     }
 
-    pub fn enable(&mut self) {
-        self.i2cdev
-            .smbus_write_byte_data(COMMAND_BIT | REGISTER_ENABLE, POWER_ON | ENABLE_AEN | ENABLE_AIEN | ENABLE_NPIEN)
-            .expect("Failed to enable device");
-            
+    pub fn enable(&mut self) -> Result<(), Error<E>> {
+        self.write_byte(
+            REGISTER_ENABLE,
+            POWER_ON | ENABLE_AEN | ENABLE_AIEN | ENABLE_NPIEN,
+        )?;
+
         self.enabled = true;
+        Ok(())
     }
 
-    pub fn disable(&mut self) {
-        self.i2cdev
-            .smbus_write_byte_data(COMMAND_BIT | REGISTER_ENABLE, POWER_OFF)
-            .expect("Failed to disable device");
+    pub fn disable(&mut self) -> Result<(), Error<E>> {
+        self.write_byte(REGISTER_ENABLE, POWER_OFF)?;
 
         self.enabled = false;
+        Ok(())
     }
 
-    pub fn set_timing_gain(&mut self, timing: IntegrationTime, gain: Gain) {
-        self.i2cdev
-            .smbus_write_byte_data(COMMAND_BIT | REGISTER_CONTROL, timing as u8 | gain as u8)
-            .expect("Failed to set device timing");
+    pub fn set_timing_gain(
+        &mut self,
+        timing: IntegrationTime,
+        gain: Gain,
+    ) -> Result<(), Error<E>> {
+        self.write_byte(REGISTER_CONTROL, timing as u8 | gain as u8)?;
 
         self.integration = timing;
         self.gain = gain;
+        Ok(())
     }
 
-    fn get_full_luminosity(&mut self) -> (u16 ,u16) {
-        let c0 = self.i2cdev
-            .smbus_read_i2c_block_data(COMMAND_BIT | REGISTER_CHAN0_LOW, 2)
-            .expect("Error reading chan 0");
+    /// Program the ALS interrupt thresholds (`AILTL`..`AIHTH`). The INT pin
+    /// asserts when a reading stays out of the `[low, high]` window for the
+    /// number of cycles configured via [`set_persist`](Self::set_persist).
+    pub fn set_als_thresholds(&mut self, low: u16, high: u16) -> Result<(), Error<E>> {
+        self.write_u16(REGISTER_AILTL, low)?;
+        self.write_u16(REGISTER_AIHTL, high)?;
+        Ok(())
+    }
 
-        // println!("c0: {}, {}", c0[0], c0[1]);
+    /// Program the no-persist ALS interrupt thresholds (`NPAILTL`..`NPAIHTH`),
+    /// which assert the interrupt immediately regardless of the persistence
+    /// filter.
+    pub fn set_no_persist_thresholds(&mut self, low: u16, high: u16) -> Result<(), Error<E>> {
+        self.write_u16(REGISTER_NPAILTL, low)?;
+        self.write_u16(REGISTER_NPAIHTL, high)?;
+        Ok(())
+    }
 
-        let c1 = self.i2cdev
-            .smbus_read_i2c_block_data(COMMAND_BIT | REGISTER_CHAN1_LOW, 2)
-            .expect("Error reading chan 1");
+    /// Set the persistence filter (`PERSIST`), i.e. how many consecutive
+    /// out-of-range integration cycles must occur before the INT pin asserts.
+    pub fn set_persist(&mut self, persist: Persist) -> Result<(), Error<E>> {
+        self.write_byte(REGISTER_PERSIST, persist as u8)
+    }
 
-        // println!("c1: {}, {}", c1[0], c1[1]);
+    /// Clear a pending ALS interrupt via the special-function command.
+    pub fn clear_interrupt(&mut self) -> Result<(), Error<E>> {
+        self.i2c.write(self.address, &[CLEAR_INTERRUPT])?;
+        Ok(())
+    }
 
-        // https://stackoverflow.com/questions/50243866/how-do-i-convert-two-u8-primitives-into-a-u16-primitive
-        let c0:u16 = ((c0[1] as u16) << 8) | c0[0] as u16;
-        let c1:u16 = ((c1[1] as u16) << 8) | c1[0] as u16;
+    fn write_u16(&mut self, register: u8, value: u16) -> Result<(), Error<E>> {
+        self.i2c.write(
+            self.address,
+            &[COMMAND_BIT | register, value as u8, (value >> 8) as u8],
+        )?;
+        Ok(())
+    }
 
-        (c0, c1)
+    /// Select which lux equation [`read`](Self::read) and friends apply to the
+    /// raw channel counts.
+    pub fn set_lux_algorithm(&mut self, algorithm: LuxAlgorithm) {
+        self.algorithm = algorithm;
     }
 
-    fn calculate_lux(&mut self, ch0: u16, ch1: u16) -> f32 {
-        let atime: f32;
-        let again: f32;
-        
-        if ch0 == 0xFFFF || ch1 == 0xFFFF {
-            panic!("overflow encountered");
+    fn write_byte(&mut self, register: u8, value: u8) -> Result<(), Error<E>> {
+        self.i2c
+            .write(self.address, &[COMMAND_BIT | register, value])?;
+        Ok(())
+    }
+
+    fn read_byte(&mut self, register: u8) -> Result<u8, Error<E>> {
+        let mut buf = [0u8; 1];
+        self.i2c
+            .write_read(self.address, &[COMMAND_BIT | register], &mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn read_u16(&mut self, register: u8) -> Result<u16, Error<E>> {
+        let mut buf = [0u8; 2];
+        self.i2c
+            .write_read(self.address, &[COMMAND_BIT | register], &mut buf)?;
+        Ok(((buf[1] as u16) << 8) | buf[0] as u16)
+    }
+
+    /// Latch the raw `(ch0, ch1)` counts without rejecting a saturated value,
+    /// so callers that need to react to saturation (e.g. auto-ranging) can.
+    fn read_raw(&mut self) -> Result<(u16, u16), Error<E>> {
+        let c0 = self.read_u16(REGISTER_CHAN0_LOW)?;
+        let c1 = self.read_u16(REGISTER_CHAN1_LOW)?;
+        Ok((c0, c1))
+    }
+
+    /// Poll `STATUS` until `AVALID` is set, then latch the raw `(ch0, ch1)`
+    /// counts. Returns [`Error::Timeout`] if the conversion never completes.
+    fn read_raw_blocking(&mut self) -> Result<(u16, u16), Error<E>> {
+        for _ in 0..AVALID_POLL_LIMIT {
+            if self.is_valid()? {
+                return self.read_raw();
+            }
         }
 
-        match self.integration {
-            IntegrationTime::IT100MS => atime = 100.0,
-            IntegrationTime::IT200MS => atime = 200.0,
-            IntegrationTime::IT300MS => atime = 300.0,
-            IntegrationTime::IT400MS => atime = 400.0,
-            IntegrationTime::IT500MS => atime = 500.0,
-            IntegrationTime::IT600MS => atime = 600.0,
+        Err(Error::Timeout)
+    }
+
+    fn get_full_luminosity(&mut self) -> Result<(u16, u16), Error<E>> {
+        let (c0, c1) = self.read_raw()?;
+
+        if c0 == 0xFFFF || c1 == 0xFFFF {
+            return Err(Error::Overflow);
         }
 
-        match self.gain {
-            Gain::LOW => again = 1.0,
-            Gain::MEDIUM => again = 25.0,
-            Gain::GHIGH => again = 428.0,
-            Gain::MAX => again = 9876.0
+        Ok((c0, c1))
+    }
+
+    fn calculate_lux(&mut self, ch0: u16, ch1: u16) -> Result<f32, Error<E>> {
+        if ch0 == 0xFFFF || ch1 == 0xFFFF {
+            return Err(Error::Overflow);
         }
 
+        let atime: f32 = match self.integration {
+            IntegrationTime::IT100MS => 100.0,
+            IntegrationTime::IT200MS => 200.0,
+            IntegrationTime::IT300MS => 300.0,
+            IntegrationTime::IT400MS => 400.0,
+            IntegrationTime::IT500MS => 500.0,
+            IntegrationTime::IT600MS => 600.0,
+        };
+
+        let again: f32 = match self.gain {
+            Gain::LOW => 1.0,
+            Gain::MEDIUM => 25.0,
+            Gain::GHIGH => 428.0,
+            Gain::MAX => 9876.0,
+        };
+
         let ch0 = ch0 as f32;
         let ch1 = ch1 as f32;
 
         let cpl = (atime * again) / LUX_DF;
-        let lux = (ch0 - ch1) * (1.0 - (ch1 / ch0)) / cpl;
+        let lux = match self.algorithm {
+            LuxAlgorithm::Formula => (ch0 - ch1) * (1.0 - (ch1 / ch0)) / cpl,
+            LuxAlgorithm::Ratio => calculate_lux2(ch0, ch1, cpl),
+        };
 
-        lux
+        Ok(lux)
+    }
+}
+
+/// Classic ratio-segmented lux approximation used by the TSL256x/TSL2563
+/// family. `cpl` is the counts-per-lux factor `(atime * again) / LUX_DF`.
+fn calculate_lux2(ch0: f32, ch1: f32, cpl: f32) -> f32 {
+    if ch0 == 0.0 {
+        return 0.0;
+    }
+
+    let ratio = ch1 / ch0;
+
+    let lux = if ratio <= 0.5 {
+        0.0304 * ch0 - 0.062 * ch0 * libm::powf(ratio, 1.4)
+    } else if ratio <= 0.61 {
+        0.0224 * ch0 - 0.031 * ch1
+    } else if ratio <= 0.80 {
+        0.0128 * ch0 - 0.0153 * ch1
+    } else if ratio <= 1.30 {
+        0.00146 * ch0 - 0.00112 * ch1
+    } else {
+        0.0
+    };
+
+    lux / cpl
+}
+
+#[cfg(feature = "linux")]
+impl Tsl2591<linux_embedded_hal::I2cdev> {
+    /// Convenience constructor that opens `/dev/i2c-1`, matching the original
+    /// Linux-only behaviour of the crate.
+    pub fn linux() -> Result<Self, Error<linux_embedded_hal::I2CError>> {
+        let dev = linux_embedded_hal::I2cdev::new("/dev/i2c-1")
+            .map_err(|e| Error::I2c(e.into()))?;
+        Tsl2591::new(dev, DEVICE_ADDR)
     }
 }
 
@@ -139,7 +414,57 @@ pub enum IntegrationTime {
     IT300MS = 0x02,
     IT400MS = 0x03,
     IT500MS = 0x04,
-    IT600MS = 0x05
+    IT600MS = 0x05,
+}
+
+impl IntegrationTime {
+    /// The next longer integration time, if any.
+    fn longer(self) -> Option<IntegrationTime> {
+        match self {
+            IntegrationTime::IT100MS => Some(IntegrationTime::IT200MS),
+            IntegrationTime::IT200MS => Some(IntegrationTime::IT300MS),
+            IntegrationTime::IT300MS => Some(IntegrationTime::IT400MS),
+            IntegrationTime::IT400MS => Some(IntegrationTime::IT500MS),
+            IntegrationTime::IT500MS => Some(IntegrationTime::IT600MS),
+            IntegrationTime::IT600MS => None,
+        }
+    }
+
+    /// The next shorter integration time, if any.
+    fn shorter(self) -> Option<IntegrationTime> {
+        match self {
+            IntegrationTime::IT100MS => None,
+            IntegrationTime::IT200MS => Some(IntegrationTime::IT100MS),
+            IntegrationTime::IT300MS => Some(IntegrationTime::IT200MS),
+            IntegrationTime::IT400MS => Some(IntegrationTime::IT300MS),
+            IntegrationTime::IT500MS => Some(IntegrationTime::IT400MS),
+            IntegrationTime::IT600MS => Some(IntegrationTime::IT500MS),
+        }
+    }
+}
+
+/// Number of consecutive out-of-range cycles required before the INT pin
+/// asserts, as encoded in the `PERSIST` register.
+#[derive(Copy, Clone)]
+pub enum Persist {
+    /// Fire on every ALS cycle, regardless of value.
+    EVERY = 0x00,
+    /// Fire on any value outside the threshold window.
+    ANY = 0x01,
+    P2 = 0x02,
+    P3 = 0x03,
+    P5 = 0x04,
+    P10 = 0x05,
+    P15 = 0x06,
+    P20 = 0x07,
+    P25 = 0x08,
+    P30 = 0x09,
+    P35 = 0x0A,
+    P40 = 0x0B,
+    P45 = 0x0C,
+    P50 = 0x0D,
+    P55 = 0x0E,
+    P60 = 0x0F,
 }
 
 #[derive(Copy, Clone)]
@@ -150,12 +475,44 @@ pub enum Gain {
     MAX = 0x30,
 }
 
+impl Gain {
+    /// The next higher gain, if any.
+    fn higher(self) -> Option<Gain> {
+        match self {
+            Gain::LOW => Some(Gain::MEDIUM),
+            Gain::MEDIUM => Some(Gain::GHIGH),
+            Gain::GHIGH => Some(Gain::MAX),
+            Gain::MAX => None,
+        }
+    }
+
+    /// The next lower gain, if any.
+    fn lower(self) -> Option<Gain> {
+        match self {
+            Gain::LOW => None,
+            Gain::MEDIUM => Some(Gain::LOW),
+            Gain::GHIGH => Some(Gain::MEDIUM),
+            Gain::MAX => Some(Gain::GHIGH),
+        }
+    }
+}
+
+/// Selectable lux equation applied to the raw channel counts.
+#[derive(Copy, Clone)]
+pub enum LuxAlgorithm {
+    /// The single empirical formula used historically by this crate.
+    Formula,
+    /// Ratio-segmented piecewise approximation (TSL256x/TSL2563 family).
+    Ratio,
+}
+
 #[cfg(test)]
 mod tests {
     #[test]
     fn calc_lux() {
-        let lux = super::calculate_lux2(430, 151);
-        println!("{}", lux);
-        assert_eq!(lux, 29.33);
+        // ratio = 151/430 <= 0.5, so the first segment applies. With cpl == 1.0
+        // the result is just the (unscaled) piecewise numerator.
+        let lux = super::calculate_lux2(430.0, 151.0, 1.0);
+        assert!((lux - 6.912).abs() < 0.01);
     }
 }